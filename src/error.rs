@@ -0,0 +1,20 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::fmt::{Debug, Display};
+
+/// Marker trait for crate-level error types used as the associated
+/// `Error` type of services and handlers.
+pub trait Error: std::error::Error + Debug + Display + Send + Sync + 'static {}
+
+impl<T> Error for T where T: std::error::Error + Debug + Display + Send + Sync + 'static {}
@@ -0,0 +1,24 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+/// A service running a loop that may fail at any iteration.
+///
+/// Implementors drive some long-running process (a socket listener, a
+/// queue consumer, etc.) and report failures through `ErrorType` rather
+/// than panicking.
+pub trait TryService {
+    type ErrorType: crate::error::Error;
+
+    /// Runs the service loop until an unrecoverable error occurs.
+    fn try_run_loop(self) -> Result<(), Self::ErrorType>;
+}
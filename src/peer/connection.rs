@@ -0,0 +1,317 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use internet2::presentation::{Error, TypedEnum, Unmarshall, Unmarshaller};
+
+/// Reserved type id for the wire-level keepalive ping sent by
+/// [`PeerSender::send_ping`]; carries an empty body. [`super::Listener`]
+/// special-cases this type id so pings never reach `Handler::handle_unknown`.
+pub(crate) const PING_TYPE_ID: u16 = 0x0001;
+
+fn is_timeout(kind: io::ErrorKind) -> bool {
+    kind == io::ErrorKind::WouldBlock || kind == io::ErrorKind::TimedOut
+}
+
+/// Progress of a message currently being read off the wire, kept across
+/// calls so that a read timeout expiring mid-message doesn't discard the
+/// bytes already received: the next call resumes exactly where the last
+/// one left off instead of reinterpreting leftover payload bytes as a
+/// fresh length prefix.
+#[derive(Default)]
+struct PartialRead {
+    len_buf: [u8; 2],
+    len_filled: usize,
+    payload: Option<Vec<u8>>,
+    payload_filled: usize,
+}
+
+impl PartialRead {
+    fn reset(&mut self) {
+        self.len_filled = 0;
+        self.payload = None;
+        self.payload_filled = 0;
+    }
+}
+
+/// A full-duplex connection to a remote peer.
+///
+/// Can be [`split`](PeerConnection::split) into independent [`PeerReceiver`]
+/// and [`PeerSender`] halves so that reading from and writing to the peer
+/// can proceed without blocking one another.
+pub struct PeerConnection {
+    stream: TcpStream,
+}
+
+impl PeerConnection {
+    pub fn with(stream: TcpStream) -> Self { Self { stream } }
+
+    /// Splits the connection into independent receive and send halves.
+    pub fn split(self) -> Result<(PeerReceiver, PeerSender), Error> {
+        let sender_stream = self.stream.try_clone().map_err(|err| Error::Io(err.kind()))?;
+        Ok((PeerReceiver { stream: self.stream, paused: false, partial: PartialRead::default() }, PeerSender {
+            stream: sender_stream,
+        }))
+    }
+}
+
+/// Receiving half of a [`PeerConnection`].
+pub struct PeerReceiver {
+    stream: TcpStream,
+    paused: bool,
+    partial: PartialRead,
+}
+
+impl PeerReceiver {
+    /// Stops actually reading from the socket until [`resume`](Self::resume)
+    /// is called. Used by [`super::Listener`] to apply backpressure when a
+    /// handler can't keep up with inbound traffic: calls to
+    /// [`RecvMessage::recv_message_timeout`] become no-ops that just wait
+    /// out the timeout instead of touching the socket.
+    pub fn pause(&mut self) { self.paused = true; }
+
+    /// Resumes reading from the socket after a [`pause`](Self::pause).
+    pub fn resume(&mut self) { self.paused = false; }
+
+    /// Whether the receiver is currently paused.
+    pub fn is_paused(&self) -> bool { self.paused }
+}
+
+/// Sending half of a [`PeerConnection`].
+pub struct PeerSender {
+    stream: TcpStream,
+}
+
+/// Ability to receive and decode a single peer message.
+pub trait RecvMessage {
+    fn recv_message<T>(&mut self, unmarshaller: &Unmarshaller<T>) -> Result<T, Error>
+    where
+        T: TypedEnum,
+        Unmarshaller<T>: Unmarshall<Data = T>;
+
+    /// Like [`recv_message`](Self::recv_message), but gives up and returns
+    /// `Ok(None)` instead of blocking indefinitely if no message arrives
+    /// within `timeout`. Used by the event loop to wake up periodically
+    /// and drive keepalive pings without a dedicated timer thread.
+    fn recv_message_timeout<T>(
+        &mut self,
+        unmarshaller: &Unmarshaller<T>,
+        timeout: Duration,
+    ) -> Result<Option<T>, Error>
+    where
+        T: TypedEnum,
+        Unmarshaller<T>: Unmarshall<Data = T>;
+}
+
+/// Ability to encode and send a single peer message.
+pub trait SendMessage {
+    fn send_message<T>(&mut self, message: &T) -> Result<(), Error>
+    where T: TypedEnum + Debug;
+
+    /// Sends a protocol-level keepalive ping, used to detect half-open
+    /// connections that would otherwise hang the event loop forever.
+    fn send_ping(&mut self) -> Result<(), Error>;
+}
+
+impl PeerReceiver {
+    /// Reads as much of the pending length-prefixed frame as arrives
+    /// before the stream's current read timeout (if any) expires,
+    /// resuming from `self.partial` on every call. Returns `Ok(None)` if
+    /// the frame is still incomplete, leaving the partial progress in
+    /// place for the next call instead of discarding it.
+    ///
+    /// Split out from [`recv_message_partial`](Self::recv_message_partial)
+    /// so the timeout-resumption state machine can be tested directly
+    /// against raw bytes, without needing a real [`Unmarshaller`].
+    fn recv_frame_partial(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        while self.partial.len_filled < self.partial.len_buf.len() {
+            match self.stream.read(&mut self.partial.len_buf[self.partial.len_filled..]) {
+                Ok(0) => return Err(Error::Io(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => self.partial.len_filled += n,
+                Err(err) if is_timeout(err.kind()) => return Ok(None),
+                Err(err) => return Err(Error::Io(err.kind())),
+            }
+        }
+
+        if self.partial.payload.is_none() {
+            let len = u16::from_be_bytes(self.partial.len_buf) as usize;
+            self.partial.payload = Some(vec![0u8; len]);
+        }
+        let payload = self.partial.payload.as_mut().expect("payload just initialized above");
+        while self.partial.payload_filled < payload.len() {
+            match self.stream.read(&mut payload[self.partial.payload_filled..]) {
+                Ok(0) => return Err(Error::Io(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => self.partial.payload_filled += n,
+                Err(err) if is_timeout(err.kind()) => return Ok(None),
+                Err(err) => return Err(Error::Io(err.kind())),
+            }
+        }
+
+        let payload = self.partial.payload.take().expect("payload filled above");
+        self.partial.reset();
+        Ok(Some(payload))
+    }
+
+    /// Reads as much of the pending message as arrives before the
+    /// stream's current read timeout (if any) expires, resuming from
+    /// `self.partial` on every call. Returns `Ok(None)` if the message is
+    /// still incomplete, leaving the partial progress in place for the
+    /// next call instead of discarding it.
+    fn recv_message_partial<T>(&mut self, unmarshaller: &Unmarshaller<T>) -> Result<Option<T>, Error>
+    where
+        T: TypedEnum,
+        Unmarshaller<T>: Unmarshall<Data = T>,
+    {
+        match self.recv_frame_partial()? {
+            Some(payload) => unmarshaller.unmarshall(&payload).map(Some).map_err(Into::into),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// Returns a connected loopback pair: `(receiver_side, writer_side)`.
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind loopback listener");
+        let addr = listener.local_addr().expect("local_addr");
+        let receiver_side = TcpStream::connect(addr).expect("connect loopback stream");
+        let (writer_side, _) = listener.accept().expect("accept loopback connection");
+        (receiver_side, writer_side)
+    }
+
+    fn receiver_with_timeout(stream: TcpStream, timeout: Duration) -> PeerReceiver {
+        stream.set_read_timeout(Some(timeout)).expect("set_read_timeout");
+        PeerReceiver { stream, paused: false, partial: PartialRead::default() }
+    }
+
+    #[test]
+    fn reads_a_frame_delivered_in_one_shot() {
+        let (stream, mut writer) = connected_pair();
+        let mut receiver = receiver_with_timeout(stream, Duration::from_millis(200));
+
+        writer.write_all(&3u16.to_be_bytes()).expect("write length");
+        writer.write_all(b"abc").expect("write payload");
+
+        let frame = receiver.recv_frame_partial().expect("recv_frame_partial").expect("frame complete");
+        assert_eq!(frame, b"abc");
+    }
+
+    #[test]
+    fn resumes_a_length_prefix_split_across_a_timeout() {
+        let (stream, mut writer) = connected_pair();
+        let mut receiver = receiver_with_timeout(stream, Duration::from_millis(50));
+
+        // Only the first byte of the 2-byte length prefix arrives.
+        writer.write_all(&3u16.to_be_bytes()[..1]).expect("write partial length");
+        assert_eq!(receiver.recv_frame_partial().expect("recv_frame_partial"), None);
+        assert_eq!(receiver.partial.len_filled, 1);
+
+        // The rest of the length prefix plus the full payload arrive later.
+        writer.write_all(&3u16.to_be_bytes()[1..]).expect("write rest of length");
+        writer.write_all(b"xyz").expect("write payload");
+
+        let frame = receiver.recv_frame_partial().expect("recv_frame_partial").expect("frame complete");
+        assert_eq!(frame, b"xyz");
+    }
+
+    #[test]
+    fn resumes_a_payload_split_across_a_timeout() {
+        let (stream, mut writer) = connected_pair();
+        let mut receiver = receiver_with_timeout(stream, Duration::from_millis(50));
+
+        writer.write_all(&4u16.to_be_bytes()).expect("write length");
+        writer.write_all(b"ab").expect("write partial payload");
+        assert_eq!(receiver.recv_frame_partial().expect("recv_frame_partial"), None);
+        assert_eq!(receiver.partial.payload_filled, 2);
+
+        writer.write_all(b"cd").expect("write rest of payload");
+        let frame = receiver.recv_frame_partial().expect("recv_frame_partial").expect("frame complete");
+        assert_eq!(frame, b"abcd");
+    }
+
+    #[test]
+    fn state_resets_for_the_next_frame_after_completion() {
+        let (stream, mut writer) = connected_pair();
+        let mut receiver = receiver_with_timeout(stream, Duration::from_millis(200));
+
+        writer.write_all(&1u16.to_be_bytes()).expect("write length");
+        writer.write_all(b"a").expect("write payload");
+        receiver.recv_frame_partial().expect("recv_frame_partial").expect("frame complete");
+
+        writer.write_all(&1u16.to_be_bytes()).expect("write length");
+        writer.write_all(b"b").expect("write payload");
+        let frame = receiver.recv_frame_partial().expect("recv_frame_partial").expect("frame complete");
+        assert_eq!(frame, b"b");
+    }
+}
+
+impl RecvMessage for PeerReceiver {
+    fn recv_message<T>(&mut self, unmarshaller: &Unmarshaller<T>) -> Result<T, Error>
+    where
+        T: TypedEnum,
+        Unmarshaller<T>: Unmarshall<Data = T>,
+    {
+        self.stream.set_read_timeout(None).map_err(|err| Error::Io(err.kind()))?;
+        loop {
+            if let Some(msg) = self.recv_message_partial(unmarshaller)? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    fn recv_message_timeout<T>(
+        &mut self,
+        unmarshaller: &Unmarshaller<T>,
+        timeout: Duration,
+    ) -> Result<Option<T>, Error>
+    where
+        T: TypedEnum,
+        Unmarshaller<T>: Unmarshall<Data = T>,
+    {
+        if self.paused {
+            thread::sleep(timeout);
+            return Ok(None);
+        }
+        self.stream.set_read_timeout(Some(timeout)).map_err(|err| Error::Io(err.kind()))?;
+        self.recv_message_partial(unmarshaller)
+    }
+}
+
+impl SendMessage for PeerSender {
+    fn send_message<T>(&mut self, message: &T) -> Result<(), Error>
+    where T: TypedEnum + Debug {
+        let payload = message.serialize();
+        let len = u16::try_from(payload.len()).map_err(|_| Error::MessageTooLarge)?;
+        self.stream.write_all(&len.to_be_bytes()).map_err(|err| Error::Io(err.kind()))?;
+        self.stream.write_all(&payload).map_err(|err| Error::Io(err.kind()))?;
+        Ok(())
+    }
+
+    fn send_ping(&mut self) -> Result<(), Error> {
+        let payload = PING_TYPE_ID.to_be_bytes();
+        self.stream.write_all(&(payload.len() as u16).to_be_bytes()).map_err(|err| Error::Io(err.kind()))?;
+        self.stream.write_all(&payload).map_err(|err| Error::Io(err.kind()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::ops::RangeInclusive;
+
+use internet2::presentation::{Error, TypedEnum, Unmarshall, Unmarshaller};
+
+use super::{Handler, MessageSendEventsProvider};
+
+/// Range of message type ids routed to a single sub-handler.
+type TypeRange = RangeInclusive<u16>;
+
+/// Aggregates several independent [`Handler`]s and dispatches each decoded
+/// message to the sub-handler registered for its type-id range.
+///
+/// Modeled on rust-lightning's `MessageHandler`, this lets a node compose,
+/// say, a gossip handler, a control handler and an RGB-specific handler
+/// into a single [`super::Listener`] without writing one monolithic
+/// `handle` function. `MessageHandler` itself implements [`Handler`], so
+/// it can be passed to `Listener` in place of any single handler.
+pub struct MessageHandler<T, E>
+where
+    T: TypedEnum,
+    E: crate::error::Error + From<Error>,
+{
+    routes: Vec<(TypeRange, Box<dyn Handler<T, Error = E>>)>,
+}
+
+impl<T, E> MessageHandler<T, E>
+where
+    T: TypedEnum,
+    E: crate::error::Error + From<Error>,
+{
+    /// Starts building a new aggregator.
+    pub fn builder() -> MessageHandlerBuilder<T, E> { MessageHandlerBuilder::new() }
+}
+
+impl<T, E> Handler<T> for MessageHandler<T, E>
+where
+    T: TypedEnum,
+    Unmarshaller<T>: Unmarshall<Data = T>,
+    E: crate::error::Error + From<Error>,
+{
+    type Error = E;
+
+    fn handle(&mut self, message: T) -> Result<(), Self::Error> {
+        let type_id = message.get_type();
+        for (range, handler) in &mut self.routes {
+            if range.contains(&type_id) {
+                return handler.handle(message);
+            }
+        }
+        trace!("No sub-handler registered for message type {:#06x}; dropping", type_id);
+        Ok(())
+    }
+
+    fn handle_unknown(&mut self, type_id: u16, payload: &[u8]) -> Result<(), Self::Error> {
+        for (range, handler) in &mut self.routes {
+            if range.contains(&type_id) {
+                return handler.handle_unknown(type_id, payload);
+            }
+        }
+        trace!("No sub-handler registered for unknown message type {:#06x}; dropping", type_id);
+        Ok(())
+    }
+
+    fn handle_err(&mut self, error: Self::Error) -> Result<(), Self::Error> {
+        // Connection-level errors aren't tied to a single message type, so
+        // they can't be routed to a specific sub-handler; propagate them.
+        Err(error)
+    }
+}
+
+impl<T, E> MessageSendEventsProvider<T> for MessageHandler<T, E>
+where
+    T: TypedEnum,
+    E: crate::error::Error + From<Error>,
+{
+    fn get_and_clear_pending_msgs(&mut self) -> Vec<T> {
+        self.routes.iter_mut().flat_map(|(_, handler)| handler.get_and_clear_pending_msgs()).collect()
+    }
+
+    fn pending_len(&self) -> usize { self.routes.iter().map(|(_, handler)| handler.pending_len()).sum() }
+}
+
+/// Builds a [`MessageHandler`] by registering sub-handlers keyed by the
+/// type-id range they should receive.
+pub struct MessageHandlerBuilder<T, E>
+where
+    T: TypedEnum,
+    E: crate::error::Error + From<Error>,
+{
+    routes: Vec<(TypeRange, Box<dyn Handler<T, Error = E>>)>,
+}
+
+impl<T, E> MessageHandlerBuilder<T, E>
+where
+    T: TypedEnum,
+    E: crate::error::Error + From<Error>,
+{
+    fn new() -> Self { Self { routes: Vec::new() } }
+
+    /// Registers `handler` as responsible for every message type in
+    /// `types`.
+    pub fn with(
+        mut self,
+        types: TypeRange,
+        handler: impl Handler<T, Error = E> + 'static,
+    ) -> Self {
+        self.routes.push((types, Box::new(handler)));
+        self
+    }
+
+    pub fn build(self) -> MessageHandler<T, E> { MessageHandler { routes: self.routes } }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::fmt;
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    struct TestMsg(u16);
+
+    impl TypedEnum for TestMsg {
+        fn get_type(&self) -> u16 { self.0 }
+    }
+
+    #[derive(Debug)]
+    struct TestError;
+
+    impl fmt::Display for TestError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "test error") }
+    }
+
+    impl std::error::Error for TestError {}
+
+    impl From<internet2::presentation::Error> for TestError {
+        fn from(_: internet2::presentation::Error) -> Self { TestError }
+    }
+
+    /// Shared sink each [`RecordingHandler`] appends `(name, type_id)` to,
+    /// so a test can see exactly which sub-handler a message reached
+    /// after ownership of the handlers has moved into the builder.
+    #[derive(Clone, Default)]
+    struct Log(Rc<RefCell<Vec<(&'static str, u16)>>>);
+
+    impl Log {
+        fn record(&self, name: &'static str, type_id: u16) { self.0.borrow_mut().push((name, type_id)); }
+
+        fn entries(&self) -> Vec<(&'static str, u16)> { self.0.borrow().clone() }
+    }
+
+    struct RecordingHandler {
+        name: &'static str,
+        log: Log,
+        pending: Vec<TestMsg>,
+    }
+
+    impl RecordingHandler {
+        fn new(name: &'static str, log: &Log) -> Self { Self { name, log: log.clone(), pending: Vec::new() } }
+    }
+
+    impl Handler<TestMsg> for RecordingHandler {
+        type Error = TestError;
+
+        fn handle(&mut self, message: TestMsg) -> Result<(), Self::Error> {
+            self.log.record(self.name, message.get_type());
+            Ok(())
+        }
+
+        fn handle_unknown(&mut self, type_id: u16, _payload: &[u8]) -> Result<(), Self::Error> {
+            self.log.record(self.name, type_id);
+            Ok(())
+        }
+
+        fn handle_err(&mut self, error: Self::Error) -> Result<(), Self::Error> { Err(error) }
+    }
+
+    impl MessageSendEventsProvider<TestMsg> for RecordingHandler {
+        fn get_and_clear_pending_msgs(&mut self) -> Vec<TestMsg> { std::mem::take(&mut self.pending) }
+
+        fn pending_len(&self) -> usize { self.pending.len() }
+    }
+
+    #[test]
+    fn routes_to_the_sub_handler_whose_range_matches() {
+        let log = Log::default();
+        let mut gossip = RecordingHandler::new("gossip", &log);
+        let mut control = RecordingHandler::new("control", &log);
+        gossip.pending.push(TestMsg(0x0100));
+        control.pending.push(TestMsg(0x0200));
+
+        let mut handler = MessageHandler::builder()
+            .with(0x0000..=0x00ff, gossip)
+            .with(0x0100..=0x01ff, control)
+            .build();
+
+        handler.handle(TestMsg(0x0050)).expect("handle");
+        handler.handle(TestMsg(0x0150)).expect("handle");
+
+        assert_eq!(log.entries(), vec![("gossip", 0x0050), ("control", 0x0150)]);
+        // pending_len/get_and_clear_pending_msgs aggregate across every
+        // route regardless of which one handled what.
+        assert_eq!(handler.pending_len(), 2);
+        assert_eq!(handler.get_and_clear_pending_msgs().len(), 2);
+        assert_eq!(handler.pending_len(), 0);
+    }
+
+    #[test]
+    fn first_registered_overlapping_range_wins() {
+        let log = Log::default();
+        let first = RecordingHandler::new("first", &log);
+        let second = RecordingHandler::new("second", &log);
+
+        // Both ranges claim 0x0050; registration order should break the tie.
+        let mut handler =
+            MessageHandler::builder().with(0x0000..=0x00ff, first).with(0x0050..=0x01ff, second).build();
+
+        handler.handle(TestMsg(0x0050)).expect("handle");
+
+        assert_eq!(log.entries(), vec![("first", 0x0050)]);
+    }
+
+    #[test]
+    fn unmatched_type_id_is_dropped_without_error() {
+        let log = Log::default();
+        let only = RecordingHandler::new("only", &log);
+        let mut handler = MessageHandler::builder().with(0x0000..=0x00ff, only).build();
+
+        // No registered sub-handler covers 0x1000/0x1001; this must be a
+        // no-op, not an error, so one handler's unrelated traffic can't
+        // take down the whole aggregator.
+        assert!(handler.handle(TestMsg(0x1000)).is_ok());
+        assert!(handler.handle_unknown(0x1001, &[]).is_ok());
+        assert!(log.entries().is_empty());
+    }
+}
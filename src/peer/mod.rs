@@ -12,14 +12,17 @@
 // If not, see <https://opensource.org/licenses/MIT>.
 
 mod connection;
+mod message_handler;
 pub mod supervisor;
 
 use std::fmt::{Debug, Display};
 use std::net::SocketAddr;
+use std::time::Duration;
 
 pub use connection::{PeerConnection, PeerReceiver, PeerSender, RecvMessage, SendMessage};
 use internet2::addr::NodeAddr;
 use internet2::presentation::{Error, TypedEnum, Unmarshall, Unmarshaller};
+pub use message_handler::{MessageHandler, MessageHandlerBuilder};
 
 use crate::node::TryService;
 
@@ -38,46 +41,188 @@ pub enum PeerSocket {
     Connect(NodeAddr),
 }
 
+/// Implemented by [`Handler`]s that want to emit outbound messages in
+/// response to inbound traffic.
+///
+/// Mirrors rust-lightning's `MessageSendEventsProvider`: after each
+/// `handle`/`handle_err` call, [`Listener`] drains any messages queued
+/// here and writes them out through the peer's [`PeerSender`] half,
+/// turning a one-directional listener into a full request/response
+/// service.
+pub trait MessageSendEventsProvider<T: TypedEnum> {
+    /// Returns and clears any messages queued for sending since the last
+    /// call. The default implementation queues nothing.
+    fn get_and_clear_pending_msgs(&mut self) -> Vec<T> { Vec::new() }
+
+    /// Depth of the queue [`get_and_clear_pending_msgs`](Self::get_and_clear_pending_msgs)
+    /// would drain right now. [`Listener`] uses this to apply read
+    /// backpressure when a handler can't keep up with a fast peer. The
+    /// default implementation is consistent with the default empty queue.
+    fn pending_len(&self) -> usize { 0 }
+}
+
 /// Trait for types handling specific LNP2P messages.
-pub trait Handler<T: TypedEnum> {
+pub trait Handler<T: TypedEnum>: MessageSendEventsProvider<T> {
     type Error: crate::error::Error + From<Error>;
 
     /// Function that processes specific peer message
     fn handle(&mut self, message: <Unmarshaller<T> as Unmarshall>::Data)
         -> Result<(), Self::Error>;
 
+    /// Called when [`Listener::run`] receives a message carrying a type id
+    /// the unmarshaller does not recognize, instead of failing the whole
+    /// event loop. `payload` is the raw, undecoded message body.
+    ///
+    /// This lets applications layer experimental or vendor-specific LNP2P
+    /// message types on top of a base protocol without the connection
+    /// dying the moment a peer sends one.
+    fn handle_unknown(&mut self, type_id: u16, payload: &[u8]) -> Result<(), Self::Error>;
+
     fn handle_err(&mut self, error: Self::Error) -> Result<(), Self::Error>;
 }
 
-pub struct Listener<H, T>
+/// [`Handler`] adapter that silently drops messages of an unrecognized
+/// type instead of erroring out, delegating everything else to `inner`.
+pub struct IgnoringHandler<H> {
+    inner: H,
+}
+
+impl<H> IgnoringHandler<H> {
+    pub fn new(inner: H) -> Self { Self { inner } }
+}
+
+impl<H, T> Handler<T> for IgnoringHandler<H>
 where
     T: TypedEnum,
     H: Handler<T>,
+{
+    type Error = H::Error;
+
+    fn handle(
+        &mut self,
+        message: <Unmarshaller<T> as Unmarshall>::Data,
+    ) -> Result<(), Self::Error> {
+        self.inner.handle(message)
+    }
+
+    fn handle_unknown(&mut self, type_id: u16, payload: &[u8]) -> Result<(), Self::Error> {
+        trace!("Ignoring message of unknown type {:#06x} ({} bytes)", type_id, payload.len());
+        Ok(())
+    }
+
+    fn handle_err(&mut self, error: Self::Error) -> Result<(), Self::Error> {
+        self.inner.handle_err(error)
+    }
+}
+
+impl<H, T> MessageSendEventsProvider<T> for IgnoringHandler<H>
+where
+    T: TypedEnum,
+    H: Handler<T>,
+{
+    fn get_and_clear_pending_msgs(&mut self) -> Vec<T> { self.inner.get_and_clear_pending_msgs() }
+
+    fn pending_len(&self) -> usize { self.inner.pending_len() }
+}
+
+/// Extracts `(type_id, payload)` from `err` if it represents a message
+/// whose type the unmarshaller does not recognize, so callers can route
+/// it to [`Handler::handle_unknown`] rather than treating it as fatal.
+fn as_unknown_message(err: &Error) -> Option<(u16, &[u8])> {
+    match err {
+        Error::UnknownDataType(type_id, payload) => Some((*type_id, payload.as_slice())),
+        _ => None,
+    }
+}
+
+/// Default interval at which [`Listener`] polls for inbound traffic and,
+/// finding none, sends a keepalive ping.
+const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Default total duration of silence tolerated before [`Listener`] gives
+/// up on a peer and returns a timeout error.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Default outbound queue depth at which [`Listener`] pauses reads.
+const DEFAULT_HIGH_WATER_MARK: usize = 1_000;
+
+/// Default outbound queue depth at which [`Listener`] resumes reads
+/// after pausing.
+const DEFAULT_LOW_WATER_MARK: usize = 100;
+
+pub struct Listener<H, T>
+where
+    T: TypedEnum + Debug,
+    H: Handler<T>,
     Unmarshaller<T>: Unmarshall,
     <Unmarshaller<T> as Unmarshall>::Data: Display + Debug,
     <Unmarshaller<T> as Unmarshall>::Error: Into<Error>,
 {
     receiver: PeerReceiver,
+    sender: PeerSender,
     handler: H,
     unmarshaller: Unmarshaller<T>,
+    keepalive_interval: Duration,
+    idle_timeout: Duration,
+    silent_intervals: u32,
+    high_water_mark: usize,
+    low_water_mark: usize,
 }
 
 impl<H, T> Listener<H, T>
 where
-    T: TypedEnum,
+    T: TypedEnum + Debug,
     H: Handler<T>,
     Unmarshaller<T>: Unmarshall,
     <Unmarshaller<T> as Unmarshall>::Data: Display + Debug,
     <Unmarshaller<T> as Unmarshall>::Error: Into<Error>,
 {
-    pub fn with(receiver: PeerReceiver, handler: H, unmarshaller: Unmarshaller<T>) -> Self {
-        Self { receiver, handler, unmarshaller }
+    pub fn with(
+        receiver: PeerReceiver,
+        sender: PeerSender,
+        handler: H,
+        unmarshaller: Unmarshaller<T>,
+    ) -> Self {
+        Self {
+            receiver,
+            sender,
+            handler,
+            unmarshaller,
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            silent_intervals: 0,
+            high_water_mark: DEFAULT_HIGH_WATER_MARK,
+            low_water_mark: DEFAULT_LOW_WATER_MARK,
+        }
+    }
+
+    /// Overrides how often the loop wakes up to check for inbound
+    /// traffic and, finding none, sends a keepalive ping.
+    pub fn with_keepalive_interval(mut self, interval: Duration) -> Self {
+        self.keepalive_interval = interval;
+        self
+    }
+
+    /// Overrides how long a peer may stay silent before the loop gives
+    /// up and reports a timeout.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Overrides the outbound queue depths at which the loop pauses and
+    /// resumes reads from the peer (see
+    /// [`MessageSendEventsProvider::pending_len`]).
+    pub fn with_backpressure_marks(mut self, high_water_mark: usize, low_water_mark: usize) -> Self {
+        self.high_water_mark = high_water_mark;
+        self.low_water_mark = low_water_mark;
+        self
     }
 }
 
 impl<H, T> TryService for Listener<H, T>
 where
-    T: TypedEnum,
+    T: TypedEnum + Debug,
     H: Handler<T>,
     Unmarshaller<T>: Unmarshall,
     <Unmarshaller<T> as Unmarshall>::Data: Display + Debug,
@@ -85,33 +230,206 @@ where
 {
     type ErrorType = H::Error;
 
-    fn try_run_loop(mut self) -> Result<(), Self::ErrorType> {
+    fn try_run_loop(self) -> Result<(), Self::ErrorType> {
         trace!("Entering event loop of the sender service");
-        loop {
-            match self.run() {
-                Ok(_) => trace!("Peer message processing complete"),
-                Err(err) => {
-                    trace!("Peer connection generated {}", err);
-                    self.handler.handle_err(err)?;
-                }
-            }
-        }
+        Err(self.run_until_error().0)
     }
 }
 
 impl<H, T> Listener<H, T>
 where
-    T: TypedEnum,
+    T: TypedEnum + Debug,
     H: Handler<T>,
     Unmarshaller<T>: Unmarshall,
     <Unmarshaller<T> as Unmarshall>::Data: Display + Debug,
     <Unmarshaller<T> as Unmarshall>::Error: Into<Error>,
 {
-    fn run(&mut self) -> Result<(), H::Error> {
+    /// Runs the event loop until an unrecoverable error, returning the
+    /// error together with whether the connection made real protocol
+    /// progress (dispatched at least one inbound message) before failing.
+    ///
+    /// [`TryService::try_run_loop`] discards the progress flag, since
+    /// `TryService` has no way to express it; [`supervisor::supervise`]
+    /// calls this directly instead so it can tell a handshake that never
+    /// got off the ground from a connection that ran a while and only
+    /// later dropped.
+    pub(crate) fn run_until_error(mut self) -> (H::Error, bool) {
+        let mut made_progress = false;
+        loop {
+            // Captured rather than propagated with `?` immediately: a
+            // handler reacting to a fatal error (e.g. with a goodbye
+            // message) still needs its reply flushed before the
+            // connection closes, so backpressure/flush must run on every
+            // iteration, including the one that's about to return.
+            let result = match self.run() {
+                Ok(true) => {
+                    trace!("Peer message processing complete");
+                    self.silent_intervals = 0;
+                    made_progress = true;
+                    Ok(())
+                }
+                Ok(false) => self.timer_tick(),
+                Err(err) => {
+                    trace!("Peer connection generated {}", err);
+                    self.handler.handle_err(err)
+                }
+            };
+            // Check the backlog the handler built up *before* draining it:
+            // checking after `flush_outbound` would always observe a
+            // just-emptied queue and the high-water branch could never
+            // fire.
+            self.apply_backpressure();
+            if let Err(err) = self.flush_outbound() {
+                return (err, made_progress);
+            }
+            if let Err(err) = result {
+                return (err, made_progress);
+            }
+        }
+    }
+
+    /// Waits for a single peer message, up to `keepalive_interval`.
+    /// Returns `Ok(true)` if a message arrived and was dispatched,
+    /// `Ok(false)` if the wait timed out with no traffic.
+    fn run(&mut self) -> Result<bool, H::Error> {
         trace!("Awaiting for peer messages...");
-        let msg = self.receiver.recv_message(&self.unmarshaller)?;
-        debug!("Processing message {}", msg);
-        trace!("Message details: {:?}", msg);
-        self.handler.handle(msg)
+        match self.receiver.recv_message_timeout(&self.unmarshaller, self.keepalive_interval) {
+            Ok(Some(msg)) => {
+                debug!("Processing message {}", msg);
+                trace!("Message details: {:?}", msg);
+                self.handler.handle(msg)?;
+                Ok(true)
+            }
+            Ok(None) => Ok(false),
+            Err(err) => match as_unknown_message(&err) {
+                Some((type_id, _)) if type_id == connection::PING_TYPE_ID => {
+                    trace!("Received keepalive ping; consuming it without involving the handler");
+                    Ok(true)
+                }
+                Some((type_id, payload)) => {
+                    trace!(
+                        "Received message of unknown type {:#06x}; routing to handle_unknown",
+                        type_id
+                    );
+                    self.handler.handle_unknown(type_id, payload)?;
+                    Ok(true)
+                }
+                None => Err(err.into()),
+            },
+        }
+    }
+
+    /// Called when `keepalive_interval` elapses with no inbound traffic.
+    /// Sends a ping to probe the connection and, once the peer has been
+    /// silent for `idle_timeout` overall, returns a timeout error so the
+    /// supervisor can tear the connection down.
+    ///
+    /// While [`apply_backpressure`](Self::apply_backpressure) has paused
+    /// reads, the socket isn't being polled at all, so any "silence" here
+    /// is self-imposed rather than evidence the peer went quiet; skip the
+    /// idle-timeout accounting entirely for those ticks.
+    fn timer_tick(&mut self) -> Result<(), H::Error> {
+        if self.receiver.is_paused() {
+            trace!("Read backpressure engaged; not counting this tick against the idle timeout");
+            return Ok(());
+        }
+        self.silent_intervals += 1;
+        trace!(
+            "No inbound traffic for {:?}; sending keepalive ping ({} consecutive)",
+            self.keepalive_interval,
+            self.silent_intervals
+        );
+        self.sender.send_ping().map_err(Into::into)?;
+        if self.keepalive_interval * self.silent_intervals >= self.idle_timeout {
+            return Err(Error::Timeout.into());
+        }
+        Ok(())
+    }
+
+    /// Drains any outbound messages the handler queued while processing
+    /// the last inbound message or error, writing each one out through
+    /// the peer's send half.
+    fn flush_outbound(&mut self) -> Result<(), H::Error> {
+        for msg in self.handler.get_and_clear_pending_msgs() {
+            debug!("Sending queued message {:?}", msg);
+            self.sender.send_message(&msg).map_err(Into::into)?;
+        }
+        Ok(())
+    }
+
+    /// Pauses reads once the handler's outbound queue depth reaches
+    /// `high_water_mark`, and resumes them once it drains back to
+    /// `low_water_mark` or below. Prevents unbounded memory growth when a handler
+    /// (e.g. one doing expensive RGB validation) can't keep up with a
+    /// fast peer.
+    ///
+    /// Must be called with the depth the handler accumulated *before*
+    /// [`flush_outbound`](Self::flush_outbound) drains it for this tick,
+    /// otherwise the high-water branch is unreachable.
+    fn apply_backpressure(&mut self) {
+        let depth = self.handler.pending_len();
+        let paused = self.receiver.is_paused();
+        let should_pause = next_pause_state(paused, depth, self.high_water_mark, self.low_water_mark);
+        if should_pause && !paused {
+            trace!(
+                "Outbound queue depth {} reached high-water mark {}; pausing reads",
+                depth,
+                self.high_water_mark
+            );
+            self.receiver.pause();
+        } else if !should_pause && paused {
+            trace!(
+                "Outbound queue depth {} fell to low-water mark {}; resuming reads",
+                depth,
+                self.low_water_mark
+            );
+            self.receiver.resume();
+        }
+    }
+}
+
+/// Pure high/low water-mark hysteresis used by [`Listener::apply_backpressure`],
+/// kept free of socket and handler types so the toggle logic itself can be
+/// exercised directly in tests: pauses once `depth` reaches `high_water_mark`
+/// and stays paused until `depth` falls back to `low_water_mark` or below.
+fn next_pause_state(currently_paused: bool, depth: usize, high_water_mark: usize, low_water_mark: usize) -> bool {
+    if !currently_paused && depth >= high_water_mark {
+        true
+    } else if currently_paused && depth <= low_water_mark {
+        false
+    } else {
+        currently_paused
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::next_pause_state;
+
+    #[test]
+    fn stays_resumed_below_high_water_mark() {
+        assert!(!next_pause_state(false, 999, 1_000, 100));
+    }
+
+    #[test]
+    fn pauses_once_high_water_mark_is_reached() {
+        assert!(next_pause_state(false, 1_000, 1_000, 100));
+    }
+
+    #[test]
+    fn stays_paused_between_low_and_high_water_marks() {
+        // Hysteresis band: once paused, depth has to fall all the way to
+        // `low_water_mark` before reads resume, not just below `high_water_mark`.
+        assert!(next_pause_state(true, 500, 1_000, 100));
+    }
+
+    #[test]
+    fn resumes_once_low_water_mark_is_reached() {
+        assert!(!next_pause_state(true, 100, 1_000, 100));
+    }
+
+    #[test]
+    fn unpaused_stream_ignores_low_water_mark() {
+        assert!(!next_pause_state(false, 0, 1_000, 100));
     }
 }
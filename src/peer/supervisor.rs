@@ -0,0 +1,141 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::fmt::{Debug, Display};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use internet2::addr::NodeAddr;
+use internet2::presentation::{Error, TypedEnum, Unmarshall, Unmarshaller};
+
+use super::{Handler, Listener, PeerConnection};
+
+/// Exponential backoff with jitter used by [`supervise`] to re-dial a
+/// `PeerSocket::Connect`-mode peer after the connection drops, instead
+/// of letting the whole service die on a transient network or Tor
+/// hiccup.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// Delay before the first reconnect attempt.
+    pub initial_delay: Duration,
+    /// Upper bound the exponential part of the delay is capped at; jitter
+    /// is added on top, so an actual delay may exceed it by up to `jitter`
+    /// times this value.
+    pub max_delay: Duration,
+    /// Multiplier applied to the delay after each failed attempt.
+    pub multiplier: f64,
+    /// Fraction of the computed delay added as random jitter, to avoid
+    /// synchronized reconnect storms across many peers.
+    pub jitter: f64,
+    /// Gives up reconnecting after this many consecutive failed
+    /// attempts. `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Computes the backoff delay for the given zero-based attempt
+    /// number, capped at `max_delay` and with random jitter applied.
+    fn delay_for(&self, attempt: u32) -> Duration {
+        let base = (self.initial_delay.as_secs_f64() * self.multiplier.powi(attempt as i32))
+            .min(self.max_delay.as_secs_f64());
+        let jitter = base * self.jitter * rand::random::<f64>();
+        Duration::from_secs_f64(base + jitter)
+    }
+}
+
+/// Dials `addr`, drives the resulting connection through a fresh
+/// [`Listener`], and on a connection-level failure re-dials the same
+/// address with exponential backoff per `policy`, rebuilding the
+/// [`PeerConnection`] and handler each time.
+///
+/// `new_handler` is invoked once per established connection so that a
+/// handler's internal state always starts clean on a new session. The
+/// attempt counter resets only once the connection has made real
+/// protocol progress (dispatched at least one message); a peer that
+/// accepts the TCP connection and then immediately drops it (a dead
+/// middlebox, a tarpit) keeps backing off instead of retrying at
+/// `initial_delay` forever.
+///
+/// `configure_listener` is applied to every freshly built [`Listener`]
+/// before it runs, so callers can carry the keepalive interval, idle
+/// timeout and backpressure marks through to each reconnection, e.g.:
+///
+/// ```ignore
+/// supervise(addr, policy, unmarshaller, new_handler, |listener| {
+///     listener.with_keepalive_interval(Duration::from_secs(15)).with_idle_timeout(Duration::from_secs(45))
+/// })
+/// ```
+///
+/// Returns `Err` once `policy.max_attempts` consecutive attempts have
+/// failed; `Listener`'s event loop never returns successfully on its
+/// own, so that's the only way this function can ever stop retrying.
+pub fn supervise<H, T>(
+    addr: NodeAddr,
+    policy: ReconnectPolicy,
+    unmarshaller: Unmarshaller<T>,
+    mut new_handler: impl FnMut() -> H,
+    mut configure_listener: impl FnMut(Listener<H, T>) -> Listener<H, T>,
+) -> Result<(), H::Error>
+where
+    T: TypedEnum + Debug,
+    H: Handler<T>,
+    Unmarshaller<T>: Unmarshall + Clone,
+    <Unmarshaller<T> as Unmarshall>::Data: Display + Debug,
+    <Unmarshaller<T> as Unmarshall>::Error: Into<Error>,
+{
+    let mut attempt = 0u32;
+    loop {
+        match dial(&addr).and_then(PeerConnection::split) {
+            Ok((receiver, sender)) => {
+                let listener = Listener::with(receiver, sender, new_handler(), unmarshaller.clone());
+                let listener = configure_listener(listener);
+                let (err, made_progress) = listener.run_until_error();
+                if made_progress {
+                    attempt = 0;
+                }
+                warn!("Connection to {} lost: {}; will reconnect", addr, err);
+            }
+            Err(err) => warn!("Failed to connect to {}: {}", addr, err),
+        }
+
+        if let Some(max) = policy.max_attempts {
+            if attempt >= max {
+                error!("Giving up on {} after {} reconnect attempts", addr, attempt);
+                return Err(Error::Timeout.into());
+            }
+        }
+        let delay = policy.delay_for(attempt);
+        attempt += 1;
+        trace!("Reconnecting to {} in {:?} (attempt {})", addr, delay, attempt);
+        thread::sleep(delay);
+    }
+}
+
+fn dial(addr: &NodeAddr) -> Result<PeerConnection, Error> {
+    let stream =
+        TcpStream::connect(addr.to_socket_addr()).map_err(|err| Error::Io(err.kind()))?;
+    Ok(PeerConnection::with(stream))
+}